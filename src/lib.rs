@@ -42,7 +42,9 @@
 //! # Ok::<(), regex_lexer::Error>(())
 //! ```
 
+use std::fmt;
 use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use regex::{Regex, RegexSet};
 pub use regex::Error;
@@ -55,10 +57,124 @@ pub struct Token<'t, K> {
     pub text: &'t str,
 }
 
+/// A 1-based line and 0-based column position within a source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pos {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The line/column span of a [Located](struct.Located.html) value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineCol {
+    pub start: Pos,
+    pub end: Pos,
+}
+
+/// Identifies which source file a [Located](struct.Located.html) value came
+/// from, so tokens lexed from several files can be merged into one stream
+/// while retaining their origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileRef(pub usize);
+
+/// Wraps a value together with its line/column span and originating file.
+/// Returned by [LocatedTokens](struct.LocatedTokens.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Located<T> {
+    pub item: T,
+    pub span: LineCol,
+    pub file: FileRef,
+}
+
+/// The callback used by [Rule::TokenFn], computing a token's kind from its
+/// span and matched text.
+type TokenFn<K> = Box<dyn Fn(Range<usize>, &str) -> K>;
+
+/// A single rule's action: ignore the match, emit a fixed kind, or compute
+/// the kind from the matched text.
+enum Rule<K> {
+    Ignore,
+    Token(K),
+    TokenFn(TokenFn<K>),
+}
+
+/// A custom matcher registered with [LexerBuilder::token_fn], given the
+/// remaining source starting at the cursor and returning the length of a
+/// match, if any.
+type ExternFn = Box<dyn Fn(&str) -> Option<usize> + Send + Sync>;
+
+/// Identifies which list a declared rule's matching logic lives in, so
+/// candidates from both can be ranked against each other by declaration
+/// order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Matcher {
+    Regex(usize),
+    Extern(usize),
+}
+
+/// Disambiguation strategy used when multiple rules match at the same
+/// position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchStrategy {
+    /// Whichever rule was defined last wins, regardless of match length.
+    LastDefined,
+    /// The longest match wins; declaration order only breaks length ties.
+    Longest,
+}
+
+/// A named lexer state created with [LexerBuilder::state]. The default/root
+/// state used by [LexerBuilder::token] and [LexerBuilder::ignore] is the
+/// first state of every [LexerBuilder].
+///
+/// Tagged with the id of the [LexerBuilder] that created it, so passing a
+/// `StateId` minted by one builder to [LexerBuilder::in_state] or
+/// [LexerBuilder::push] on a different builder panics at the point of
+/// misuse instead of corrupting that builder's state table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StateId {
+    index: usize,
+    builder_id: u64,
+}
+
+impl StateId {
+    /// The implicit root state every lexer starts in. Used internally once a
+    /// [LexerBuilder] has been consumed by `build`, where `StateId`s no
+    /// longer need to carry a meaningful `builder_id`.
+    fn root() -> Self {
+        StateId { index: 0, builder_id: 0 }
+    }
+}
+
+/// An action against the state stack taken when a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StateTransition {
+    None,
+    Push(StateId),
+    Pop,
+}
+
+/// A counter assigning each [LexerBuilder] a unique id, so a [StateId] can
+/// be traced back to the builder that minted it.
+static NEXT_BUILDER_ID: AtomicU64 = AtomicU64::new(0);
+
 /// Builder struct for [Lexer](struct.Lexer.html).
 pub struct LexerBuilder<'r, K> {
     regexes: Vec<&'r str>,
-    kinds: Vec<Option<K>>,
+    rules: Vec<Rule<K>>,
+    regex_order: Vec<usize>,
+    regex_states: Vec<StateId>,
+    regex_transitions: Vec<StateTransition>,
+    externs: Vec<ExternFn>,
+    extern_kinds: Vec<K>,
+    extern_order: Vec<usize>,
+    extern_states: Vec<StateId>,
+    extern_transitions: Vec<StateTransition>,
+    error_kind: Option<K>,
+    strategy: MatchStrategy,
+    num_states: usize,
+    current_state: StateId,
+    last_rule: Option<Matcher>,
+    builder_id: u64,
 }
 
 impl<'r, K> Default for LexerBuilder<'r, K> {
@@ -70,12 +186,33 @@ impl<'r, K> Default for LexerBuilder<'r, K> {
 impl<'r, K> LexerBuilder<'r, K> {
     /// Create a new [LexerBuilder](struct.LexerBuilder.html).
     pub fn new() -> Self {
+        let builder_id = NEXT_BUILDER_ID.fetch_add(1, Ordering::Relaxed);
         LexerBuilder {
             regexes: Vec::new(),
-            kinds: Vec::new(),
+            rules: Vec::new(),
+            regex_order: Vec::new(),
+            regex_states: Vec::new(),
+            regex_transitions: Vec::new(),
+            externs: Vec::new(),
+            extern_kinds: Vec::new(),
+            extern_order: Vec::new(),
+            extern_states: Vec::new(),
+            extern_transitions: Vec::new(),
+            error_kind: None,
+            strategy: MatchStrategy::LastDefined,
+            num_states: 1,
+            current_state: StateId { index: 0, builder_id },
+            last_rule: None,
+            builder_id,
         }
     }
 
+    /// The declaration order of the next rule, used to rank matches from the
+    /// `RegexSet` against matches from custom matchers.
+    fn next_order(&self) -> usize {
+        self.regex_order.len() + self.extern_order.len()
+    }
+
     /// Add a new token that matches the regular expression `re`.
     /// This uses the same syntax as the [regex](http://docs.rs/regex/1/regex) crate.
     ///
@@ -136,35 +273,317 @@ impl<'r, K> LexerBuilder<'r, K> {
     /// ```
     pub fn token(mut self, re: &'r str, kind: K) -> Self
     {
+        self.regex_order.push(self.next_order());
+        self.regexes.push(re);
+        self.rules.push(Rule::Token(kind));
+        self.regex_states.push(self.current_state);
+        self.regex_transitions.push(StateTransition::None);
+        self.last_rule = Some(Matcher::Regex(self.regexes.len() - 1));
+        self
+    }
+
+    /// Add a new token that matches the regular expression `re`, computing its
+    /// kind from the matched text instead of using a fixed value.
+    ///
+    /// `f` is called with the span and text of the match, so it can carry
+    /// parsed data (e.g. an integer) inside the returned `K`. Iterating over
+    /// the tokens requires `K: Copy`, so the payload must itself be `Copy`;
+    /// an owned value such as a `String` cannot be carried.
+    /// ```
+    /// use regex_lexer::{LexerBuilder, Token};
+    ///
+    /// #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    /// enum Tok {
+    ///     Num(i64),
+    /// }
+    ///
+    /// let lexer = LexerBuilder::new()
+    ///     .token_with(r"[0-9]+", |_span, text| Tok::Num(text.parse().unwrap()))
+    ///     .ignore(r"\s+")
+    ///     .build()?;
+    ///
+    /// assert_eq!(
+    ///     lexer.tokens("1 2 3").collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token { kind: Tok::Num(1), span: 0..1, text: "1" },
+    ///         Token { kind: Tok::Num(2), span: 2..3, text: "2" },
+    ///         Token { kind: Tok::Num(3), span: 4..5, text: "3" },
+    ///     ],
+    /// );
+    /// # Ok::<(), regex::Error>(())
+    /// ```
+    pub fn token_with<F>(mut self, re: &'r str, f: F) -> Self
+    where
+        F: Fn(Range<usize>, &str) -> K + 'static,
+    {
+        self.regex_order.push(self.next_order());
         self.regexes.push(re);
-        self.kinds.push(Some(kind));
+        self.rules.push(Rule::TokenFn(Box::new(f)));
+        self.regex_states.push(self.current_state);
+        self.regex_transitions.push(StateTransition::None);
+        self.last_rule = Some(Matcher::Regex(self.regexes.len() - 1));
         self
     }
 
     /// Add a new regex which if matched will ignore the matched text.
     pub fn ignore(mut self, re: &'r str) -> Self {
+        self.regex_order.push(self.next_order());
         self.regexes.push(re);
-        self.kinds.push(None);
+        self.rules.push(Rule::Ignore);
+        self.regex_states.push(self.current_state);
+        self.regex_transitions.push(StateTransition::None);
+        self.last_rule = Some(Matcher::Regex(self.regexes.len() - 1));
+        self
+    }
+
+    /// Add a new token matched by a custom function instead of a regex, for
+    /// constructs a single regular expression can't express — nested block
+    /// comments, indentation-sensitive layout, or arbitrarily nested escapes.
+    ///
+    /// `matcher` is called with the remaining source starting at the cursor
+    /// and should return `Some(byte_len)` of a match starting there, or
+    /// `None` if it doesn't match. As with [LexerBuilder::token], if a regex
+    /// rule and a custom matcher both match at the same position, whichever
+    /// was defined last is given priority.
+    /// ```
+    /// use regex_lexer::{LexerBuilder, Token};
+    ///
+    /// #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    /// enum Tok {
+    ///     Comment,
+    /// }
+    ///
+    /// // Matches a single `#` comment, stopping at (but not past) the first
+    /// // newline -- trivial to express as a regex, but illustrates the shape
+    /// // a matcher for e.g. nested block comments would take.
+    /// let lexer = LexerBuilder::new()
+    ///     .token_fn(Tok::Comment, |s| {
+    ///         s.starts_with('#').then(|| s.find('\n').unwrap_or(s.len()))
+    ///     })
+    ///     .ignore(r"\s+")
+    ///     .build()?;
+    ///
+    /// assert_eq!(
+    ///     lexer.tokens("# a comment").collect::<Vec<_>>(),
+    ///     vec![Token { kind: Tok::Comment, span: 0..11, text: "# a comment" }],
+    /// );
+    /// # Ok::<(), regex::Error>(())
+    /// ```
+    pub fn token_fn<F>(mut self, kind: K, matcher: F) -> Self
+    where
+        F: Fn(&str) -> Option<usize> + Send + Sync + 'static,
+    {
+        self.extern_order.push(self.next_order());
+        self.externs.push(Box::new(matcher));
+        self.extern_kinds.push(kind);
+        self.extern_states.push(self.current_state);
+        self.extern_transitions.push(StateTransition::None);
+        self.last_rule = Some(Matcher::Extern(self.externs.len() - 1));
+        self
+    }
+
+    /// Register a token kind to emit for input that no rule matches, instead
+    /// of panicking.
+    ///
+    /// When [Tokens::next](struct.Tokens.html) can't match any rule at the
+    /// current position, it scans forward to the next position where some
+    /// rule matches (or to the end of the source) and emits a single token
+    /// of `kind` spanning the skipped text. Without an error kind registered,
+    /// unmatched input causes a panic; see
+    /// [Lexer::try_tokens](struct.Lexer.html#method.try_tokens) for a
+    /// non-panicking alternative that doesn't require this.
+    /// ```
+    /// use regex_lexer::{LexerBuilder, Token};
+    ///
+    /// #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    /// enum Tok {
+    ///     Num,
+    ///     Error,
+    /// }
+    ///
+    /// let lexer = LexerBuilder::new()
+    ///     .token(r"[0-9]+", Tok::Num)
+    ///     .ignore(r"\s+")
+    ///     .error_token(Tok::Error)
+    ///     .build()?;
+    ///
+    /// assert_eq!(
+    ///     lexer.tokens("1 @ 2").collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token { kind: Tok::Num, span: 0..1, text: "1" },
+    ///         Token { kind: Tok::Error, span: 2..3, text: "@" },
+    ///         Token { kind: Tok::Num, span: 4..5, text: "2" },
+    ///     ],
+    /// );
+    /// # Ok::<(), regex::Error>(())
+    /// ```
+    pub fn error_token(mut self, kind: K) -> Self {
+        self.error_kind = Some(kind);
+        self
+    }
+
+    /// Switch to "maximal munch" disambiguation: among all rules that match
+    /// at the cursor, the one with the longest match wins, with declaration
+    /// order only used to break ties between equal-length matches.
+    ///
+    /// By default, whichever rule was defined last wins regardless of match
+    /// length -- see [LexerBuilder::token](struct.LexerBuilder.html#method.token).
+    /// ```
+    /// use regex_lexer::{LexerBuilder, Token};
+    ///
+    /// #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    /// enum Tok {
+    ///     Assign,
+    ///     Eq,
+    /// }
+    ///
+    /// let lexer = LexerBuilder::new()
+    ///     .token(r"=", Tok::Assign)
+    ///     .token(r"==", Tok::Eq)
+    ///     .longest_match()
+    ///     .build()?;
+    ///
+    /// assert_eq!(
+    ///     lexer.tokens("==").collect::<Vec<_>>(),
+    ///     vec![Token { kind: Tok::Eq, span: 0..2, text: "==" }],
+    /// );
+    /// # Ok::<(), regex::Error>(())
+    /// ```
+    pub fn longest_match(mut self) -> Self {
+        self.strategy = MatchStrategy::Longest;
+        self
+    }
+
+    /// Define a new named lexer state, returning a [StateId](struct.StateId.html)
+    /// used with [LexerBuilder::in_state](struct.LexerBuilder.html#method.in_state)
+    /// to scope rules to it and with
+    /// [LexerBuilder::push](struct.LexerBuilder.html#method.push) to enter it.
+    ///
+    /// `name` is solely for readability at the call site; the builder
+    /// doesn't store or look anything up by it.
+    pub fn state(&mut self, _name: &str) -> StateId {
+        let id = StateId { index: self.num_states, builder_id: self.builder_id };
+        self.num_states += 1;
+        id
+    }
+
+    /// Panics if `state` was minted by a different [LexerBuilder] than
+    /// `self` -- mixing the two would otherwise surface as an
+    /// index-out-of-bounds panic in [LexerBuilder::build], or later, deep
+    /// inside the lexing loop.
+    fn check_state(&self, state: StateId) {
+        assert_eq!(
+            state.builder_id, self.builder_id,
+            "regex-lexer: a StateId can only be used with the LexerBuilder that created it"
+        );
+    }
+
+    /// Scope subsequently added rules to `state` instead of the default/root
+    /// state, until the next call to `in_state`.
+    pub fn in_state(mut self, state: StateId) -> Self {
+        self.check_state(state);
+        self.current_state = state;
+        self
+    }
+
+    /// Make the most recently added rule push `state` onto the lexer's state
+    /// stack when it matches, so later input is matched against `state`'s
+    /// rules until a matching [LexerBuilder::pop](struct.LexerBuilder.html#method.pop)
+    /// rule fires.
+    ///
+    /// Rules in the default/root state are unaffected unless this (or
+    /// [LexerBuilder::pop](struct.LexerBuilder.html#method.pop)) is used, so
+    /// existing lexers that don't use states behave exactly as before.
+    /// ```
+    /// use regex_lexer::{LexerBuilder, Token};
+    ///
+    /// #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    /// enum Tok {
+    ///     Quote,
+    ///     StrText,
+    /// }
+    ///
+    /// let mut builder = LexerBuilder::new();
+    /// let string_state = builder.state("string");
+    /// let lexer = builder
+    ///     .token(r#"""#, Tok::Quote).push(string_state)
+    ///     .in_state(string_state)
+    ///     .token(r#"[^"]+"#, Tok::StrText)
+    ///     .token(r#"""#, Tok::Quote).pop()
+    ///     .build()?;
+    ///
+    /// assert_eq!(
+    ///     lexer.tokens(r#""hello""#).collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token { kind: Tok::Quote, span: 0..1, text: "\"" },
+    ///         Token { kind: Tok::StrText, span: 1..6, text: "hello" },
+    ///         Token { kind: Tok::Quote, span: 6..7, text: "\"" },
+    ///     ],
+    /// );
+    /// # Ok::<(), regex::Error>(())
+    /// ```
+    pub fn push(mut self, state: StateId) -> Self {
+        self.check_state(state);
+        self.set_last_transition(StateTransition::Push(state));
         self
     }
 
+    /// Make the most recently added rule pop the lexer's state stack when it
+    /// matches, returning to whichever state was active before the matching
+    /// [LexerBuilder::push](struct.LexerBuilder.html#method.push).
+    pub fn pop(mut self) -> Self {
+        self.set_last_transition(StateTransition::Pop);
+        self
+    }
+
+    fn set_last_transition(&mut self, transition: StateTransition) {
+        match self.last_rule {
+            Some(Matcher::Regex(i)) => self.regex_transitions[i] = transition,
+            Some(Matcher::Extern(i)) => self.extern_transitions[i] = transition,
+            None => panic!(
+                "regex-lexer: `push`/`pop` must follow a `token`, `ignore`, \
+                 `token_with`, or `token_fn` call"
+            ),
+        }
+    }
+
     /// Construct a [Lexer](struct.Lexer.html) which matches these tokens.
     ///
     /// ## Errors
     ///
     /// If a regex cannot be compiled, a [Error](https://docs.rs/regex/1/regex/enum.Error.html) is returned.
     pub fn build(self) -> Result<Lexer<K>, Error> {
-        let regexes = self.regexes.into_iter().map(|r| format!("^{}", r));
-        let regex_set = RegexSet::new(regexes)?;
-        let mut regexes = Vec::new();
-        for pattern in regex_set.patterns() {
-            regexes.push(Regex::new(pattern)?);
+        let num_states = self.num_states;
+        let mut patterns: Vec<Vec<String>> = vec![Vec::new(); num_states];
+        let mut to_orig: Vec<Vec<usize>> = vec![Vec::new(); num_states];
+        for (i, re) in self.regexes.iter().enumerate() {
+            let state = self.regex_states[i].index;
+            patterns[state].push(format!("^{}", re));
+            to_orig[state].push(i);
+        }
+
+        let mut states = Vec::with_capacity(num_states);
+        for (patterns, to_orig) in patterns.into_iter().zip(to_orig) {
+            let regex_set = RegexSet::new(patterns)?;
+            let mut regexes = Vec::new();
+            for pattern in regex_set.patterns() {
+                regexes.push(Regex::new(pattern)?);
+            }
+            states.push(StateRules { regex_set, regexes, to_orig });
         }
 
         Ok(Lexer {
-            kinds: self.kinds,
-            regexes,
-            regex_set,
+            rules: self.rules,
+            regex_order: self.regex_order,
+            regex_transitions: self.regex_transitions,
+            states,
+            externs: self.externs,
+            extern_kinds: self.extern_kinds,
+            extern_order: self.extern_order,
+            extern_states: self.extern_states,
+            extern_transitions: self.extern_transitions,
+            error_kind: self.error_kind,
+            strategy: self.strategy,
         })
     }
 }
@@ -199,11 +618,35 @@ impl<'r, K> LexerBuilder<'r, K> {
 /// # );
 /// # Ok::<(), regex::Error>(())
 /// ```
-#[derive(Debug)]
 pub struct Lexer<K> {
-    kinds: Vec<Option<K>>,
-    regexes: Vec<Regex>,
+    rules: Vec<Rule<K>>,
+    regex_order: Vec<usize>,
+    regex_transitions: Vec<StateTransition>,
+    states: Vec<StateRules>,
+    externs: Vec<ExternFn>,
+    extern_kinds: Vec<K>,
+    extern_order: Vec<usize>,
+    extern_states: Vec<StateId>,
+    extern_transitions: Vec<StateTransition>,
+    error_kind: Option<K>,
+    strategy: MatchStrategy,
+}
+
+/// The compiled `RegexSet` for a single lexer state, plus a mapping from its
+/// local pattern index back to the rule's index in `Lexer`'s `rules` /
+/// `regex_order` / `regex_transitions` vectors.
+struct StateRules {
     regex_set: RegexSet,
+    regexes: Vec<Regex>,
+    to_orig: Vec<usize>,
+}
+
+/// Manual impl since `Rule::TokenFn` and the extern matchers hold
+/// `Box<dyn Fn>`, which isn't `Debug`.
+impl<K: fmt::Debug> fmt::Debug for Lexer<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lexer").finish_non_exhaustive()
+    }
 }
 
 impl<K> Lexer<K> {
@@ -213,52 +656,427 @@ impl<K> Lexer<K> {
     }
 
     /// Return an iterator over all matched tokens.
+    ///
+    /// If the source contains text that no rule matches, the skipped text is
+    /// emitted as a token of the kind registered with
+    /// [LexerBuilder::error_token](struct.LexerBuilder.html#method.error_token),
+    /// or, if none was registered, this panics. Use
+    /// [Lexer::try_tokens](struct.Lexer.html#method.try_tokens) to recover
+    /// from unmatched input without registering an error kind.
     pub fn tokens<'l, 't>(&'l self, source: &'t str) -> Tokens<'l, 't, K> {
         Tokens {
             lexer: self,
             source,
             position: 0,
+            stack: vec![StateId::root()],
+        }
+    }
+
+    /// Return an iterator over all matched tokens, yielding a
+    /// [LexError](struct.LexError.html) instead of panicking when no rule
+    /// matches the input.
+    pub fn try_tokens<'l, 't>(&'l self, source: &'t str) -> TryTokens<'l, 't, K> {
+        TryTokens {
+            lexer: self,
+            source,
+            position: 0,
+            stack: vec![StateId::root()],
+        }
+    }
+
+    /// Return an iterator over all matched tokens, each wrapped in a
+    /// [Located](struct.Located.html) giving its 1-based line and 0-based
+    /// column span, tagged with `file` so tokens lexed from several sources
+    /// can be merged into one stream.
+    /// ```
+    /// use regex_lexer::{FileRef, LexerBuilder, Located, LineCol, Pos, Token};
+    ///
+    /// #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    /// enum Tok {
+    ///     Ident,
+    /// }
+    ///
+    /// let lexer = LexerBuilder::new()
+    ///     .token(r"[a-z]+", Tok::Ident)
+    ///     .ignore(r"\s+")
+    ///     .build()?;
+    ///
+    /// assert_eq!(
+    ///     lexer.tokens_located("one\ntwo", FileRef(0)).collect::<Vec<_>>(),
+    ///     vec![
+    ///         Located {
+    ///             item: Token { kind: Tok::Ident, span: 0..3, text: "one" },
+    ///             span: LineCol { start: Pos { line: 1, column: 0 }, end: Pos { line: 1, column: 3 } },
+    ///             file: FileRef(0),
+    ///         },
+    ///         Located {
+    ///             item: Token { kind: Tok::Ident, span: 4..7, text: "two" },
+    ///             span: LineCol { start: Pos { line: 2, column: 0 }, end: Pos { line: 2, column: 3 } },
+    ///             file: FileRef(0),
+    ///         },
+    ///     ],
+    /// );
+    /// # Ok::<(), regex::Error>(())
+    /// ```
+    pub fn tokens_located<'l, 't>(&'l self, source: &'t str, file: FileRef) -> LocatedTokens<'l, 't, K> {
+        LocatedTokens {
+            tokens: self.tokens(source),
+            file,
+            byte: 0,
+            line: 1,
+            column: 0,
+        }
+    }
+}
+
+/// Whether `(order, len)` should replace `(best_order, best_len)` as the
+/// winning match, under the lexer's disambiguation strategy.
+fn beats(strategy: MatchStrategy, order: usize, len: usize, best_order: usize, best_len: usize) -> bool {
+    match strategy {
+        MatchStrategy::LastDefined => order > best_order,
+        MatchStrategy::Longest => (len, order) > (best_len, best_order),
+    }
+}
+
+/// Find the highest-priority rule that matches at the start of `string`,
+/// among both the current state's `RegexSet` and the custom matchers
+/// attached to `state`, returning the match length and which rule matched.
+fn find_match<K>(lexer: &Lexer<K>, state: StateId, string: &str) -> Option<(usize, Matcher)> {
+    let mut best: Option<(usize, usize, Matcher)> = None; // (order, len, matcher)
+
+    let state_rules = &lexer.states[state.index];
+    for local_i in state_rules.regex_set.matches(string) {
+        let i = state_rules.to_orig[local_i];
+        let m = state_rules.regexes[local_i].find(string).unwrap();
+        assert!(m.start() == 0);
+        let (order, len) = (lexer.regex_order[i], m.end());
+        if best
+            .as_ref()
+            .is_none_or(|(o, l, _)| beats(lexer.strategy, order, len, *o, *l))
+        {
+            best = Some((order, len, Matcher::Regex(i)));
+        }
+    }
+
+    for (i, matcher) in lexer.externs.iter().enumerate() {
+        if lexer.extern_states[i].index != state.index {
+            continue;
+        }
+        if let Some(len) = matcher(string) {
+            let order = lexer.extern_order[i];
+            if best
+                .as_ref()
+                .is_none_or(|(o, l, _)| beats(lexer.strategy, order, len, *o, *l))
+            {
+                best = Some((order, len, Matcher::Extern(i)));
+            }
+        }
+    }
+
+    best.map(|(_, len, matcher)| (len, matcher))
+}
+
+/// Round `i` up to the next `char` boundary in `source`.
+fn ceil_char_boundary(source: &str, mut i: usize) -> usize {
+    while i < source.len() && !source.is_char_boundary(i) {
+        i += 1;
+    }
+    i
+}
+
+/// Whether some rule -- regex or custom matcher -- attached to `state`
+/// matches at the start of `string`.
+fn has_match<K>(lexer: &Lexer<K>, state: StateId, string: &str) -> bool {
+    lexer.states[state.index].regex_set.is_match(string)
+        || lexer
+            .externs
+            .iter()
+            .enumerate()
+            .any(|(i, matcher)| lexer.extern_states[i].index == state.index && matcher(string).is_some())
+}
+
+/// Scan forward from `position` to the next position where some rule in
+/// `state` matches, or to the end of `source`.
+fn skip_to_next_match<K>(lexer: &Lexer<K>, state: StateId, source: &str, position: usize) -> usize {
+    let mut end = ceil_char_boundary(source, position + 1);
+    while end < source.len() && !has_match(lexer, state, &source[end..]) {
+        end = ceil_char_boundary(source, end + 1);
+    }
+    end
+}
+
+/// Apply a matched rule's state transition to the state stack, returning the
+/// state the lexer should resume in. Popping the last remaining state is a
+/// no-op, so a stray `pop` in the root state can never empty the stack.
+fn apply_transition(stack: &mut Vec<StateId>, transition: StateTransition) -> StateId {
+    match transition {
+        StateTransition::None => {}
+        StateTransition::Push(state) => stack.push(state),
+        StateTransition::Pop => {
+            if stack.len() > 1 {
+                stack.pop();
+            }
+        }
+    }
+    *stack.last().unwrap()
+}
+
+/// The outcome of one [step] of the shared matching loop behind
+/// [Tokens::next] and [TryTokens::next]: either a token to emit, or a span
+/// of unmatched input for the caller to turn into an error or a panic.
+enum StepResult<'t, K> {
+    Token(Token<'t, K>),
+    Unmatched(Range<usize>, &'t str),
+}
+
+/// Match one rule (skipping over any `Rule::Ignore` matches and applying
+/// their state transitions along the way) or, failing that, skip one span of
+/// unmatched input, advancing `*position` and `stack` in either case.
+/// Returns `None` once `source` is exhausted. Shared by [Tokens::next] and
+/// [TryTokens::next], which differ only in how they report unmatched input.
+fn step<'t, K: Copy>(
+    lexer: &Lexer<K>,
+    source: &'t str,
+    position: &mut usize,
+    stack: &mut Vec<StateId>,
+) -> Option<StepResult<'t, K>> {
+    loop {
+        if *position == source.len() {
+            return None;
+        }
+
+        let state = *stack.last().unwrap();
+        let string = &source[*position..];
+        match find_match(lexer, state, string) {
+            Some((len, matcher)) => {
+                let span = *position..*position + len;
+                let text = &source[span.clone()];
+                *position += len;
+                match matcher {
+                    Matcher::Regex(i) => {
+                        apply_transition(stack, lexer.regex_transitions[i]);
+                        match &lexer.rules[i] {
+                            Rule::Ignore => {}
+                            Rule::Token(kind) => {
+                                return Some(StepResult::Token(Token { kind: *kind, span, text }))
+                            }
+                            Rule::TokenFn(f) => {
+                                return Some(StepResult::Token(Token {
+                                    kind: f(span.clone(), text),
+                                    span,
+                                    text,
+                                }))
+                            }
+                        }
+                    }
+                    Matcher::Extern(i) => {
+                        apply_transition(stack, lexer.extern_transitions[i]);
+                        let kind = lexer.extern_kinds[i];
+                        return Some(StepResult::Token(Token { kind, span, text }));
+                    }
+                }
+            }
+            None => {
+                let end = skip_to_next_match(lexer, state, source, *position);
+                let span = *position..end;
+                let text = &source[span.clone()];
+                *position = end;
+                return Some(StepResult::Unmatched(span, text));
+            }
         }
     }
 }
 
 /// The type returned by [Lexer::tokens](struct.Lexer.html#method.tokens).
-#[derive(Debug)]
 pub struct Tokens<'l, 't, K> {
     lexer: &'l Lexer<K>,
     source: &'t str,
     position: usize,
+    stack: Vec<StateId>,
+}
+
+/// Manual impl since `lexer` is a `&Lexer<K>`, which can't derive `Debug`.
+impl<'l, 't, K: fmt::Debug> fmt::Debug for Tokens<'l, 't, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tokens")
+            .field("source", &self.source)
+            .field("position", &self.position)
+            .field("stack", &self.stack)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<'l, 't, K: Copy> Iterator for Tokens<'l, 't, K> {
     type Item = Token<'t, K>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if self.position == self.source.len() {
-                return None;
-            }
+        match step(self.lexer, self.source, &mut self.position, &mut self.stack)? {
+            StepResult::Token(token) => Some(token),
+            StepResult::Unmatched(span, text) => match self.lexer.error_kind {
+                Some(kind) => Some(Token { kind, span, text }),
+                None => panic!(
+                    "regex-lexer: no rule matched {:?} at byte {}; \
+                     register an error kind with `LexerBuilder::error_token` \
+                     or use `Lexer::try_tokens` to recover from this",
+                    text, span.start
+                ),
+            },
+        }
+    }
+}
+
+/// An error produced when no rule matches the input, returned by
+/// [TryTokens](struct.TryTokens.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexError<'t> {
+    pub span: Range<usize>,
+    pub text: &'t str,
+}
+
+/// The type returned by [Lexer::try_tokens](struct.Lexer.html#method.try_tokens).
+pub struct TryTokens<'l, 't, K> {
+    lexer: &'l Lexer<K>,
+    source: &'t str,
+    position: usize,
+    stack: Vec<StateId>,
+}
+
+/// Manual impl since `lexer` is a `&Lexer<K>`, which can't derive `Debug`.
+impl<'l, 't, K: fmt::Debug> fmt::Debug for TryTokens<'l, 't, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TryTokens")
+            .field("source", &self.source)
+            .field("position", &self.position)
+            .field("stack", &self.stack)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'l, 't, K: Copy> Iterator for TryTokens<'l, 't, K> {
+    type Item = Result<Token<'t, K>, LexError<'t>>;
 
-            let string = &self.source[self.position..];
-            let match_set = self.lexer.regex_set.matches(string);
-            let (len, i) = match_set
-                .into_iter()
-                .map(|i: usize| {
-                    let m = self.lexer.regexes[i].find(string).unwrap();
-                    assert!(m.start() == 0);
-                    (m.end(), i)
-                })
-                //.max_by_key(|(len, _)| *len)
-                .next_back()
-                .unwrap();
-
-            let span = self.position..self.position + len;
-            let text = &self.source[span.clone()];
-            self.position += len;
-            match self.lexer.kinds[i] {
-                Some(kind) => return Some(Token { kind, span, text}),
-                None => {}
+    fn next(&mut self) -> Option<Self::Item> {
+        match step(self.lexer, self.source, &mut self.position, &mut self.stack)? {
+            StepResult::Token(token) => Some(Ok(token)),
+            StepResult::Unmatched(span, text) => Some(Err(LexError { span, text })),
+        }
+    }
+}
+
+/// Whether `byte` is the first byte of a UTF-8 sequence (as opposed to a
+/// continuation byte), so multi-byte characters count as a single column.
+fn is_char_start(byte: u8) -> bool {
+    byte & 0b1100_0000 != 0b1000_0000
+}
+
+/// The type returned by [Lexer::tokens_located](struct.Lexer.html#method.tokens_located).
+pub struct LocatedTokens<'l, 't, K> {
+    tokens: Tokens<'l, 't, K>,
+    file: FileRef,
+    byte: usize,
+    line: usize,
+    column: usize,
+}
+
+/// Manual impl since `tokens` is a [Tokens](struct.Tokens.html), which can't
+/// derive `Debug`.
+impl<'l, 't, K: fmt::Debug> fmt::Debug for LocatedTokens<'l, 't, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocatedTokens")
+            .field("file", &self.file)
+            .field("byte", &self.byte)
+            .field("line", &self.line)
+            .field("column", &self.column)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'l, 't, K> LocatedTokens<'l, 't, K> {
+    /// Advance the running line/column counter from `self.byte` to `target`,
+    /// returning the position at `target`.
+    fn advance_to(&mut self, target: usize) -> Pos {
+        for &byte in &self.tokens.source.as_bytes()[self.byte..target] {
+            if byte == b'\n' {
+                self.line += 1;
+                self.column = 0;
+            } else if is_char_start(byte) {
+                self.column += 1;
             }
         }
+        self.byte = target;
+        Pos { line: self.line, column: self.column }
+    }
+}
+
+impl<'l, 't, K: Copy> Iterator for LocatedTokens<'l, 't, K> {
+    type Item = Located<Token<'t, K>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.tokens.next()?;
+        let start = self.advance_to(token.span.start);
+        let end = self.advance_to(token.span.end);
+        Some(Located {
+            item: token,
+            span: LineCol { start, end },
+            file: self.file,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    enum Tok {
+        A,
+        B,
+    }
+
+    #[test]
+    fn stray_pop_in_root_state_is_a_no_op() {
+        let lexer = LexerBuilder::new()
+            .token(r"a", Tok::A).pop()
+            .ignore(r"\s+")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            lexer.tokens("a a a").collect::<Vec<_>>(),
+            vec![
+                Token { kind: Tok::A, span: 0..1, text: "a" },
+                Token { kind: Tok::A, span: 2..3, text: "a" },
+                Token { kind: Tok::A, span: 4..5, text: "a" },
+            ],
+        );
+    }
+
+    #[test]
+    fn unmatched_multibyte_input_is_not_split_at_a_byte_boundary() {
+        let lexer = LexerBuilder::new().token(r"[0-9]+", Tok::A).build().unwrap();
+
+        assert_eq!(
+            lexer.try_tokens("1あ2").collect::<Vec<_>>(),
+            vec![
+                Ok(Token { kind: Tok::A, span: 0..1, text: "1" }),
+                Err(LexError { span: 1..4, text: "あ" }),
+                Ok(Token { kind: Tok::A, span: 4..5, text: "2" }),
+            ],
+        );
+    }
+
+    #[test]
+    fn longest_match_prefers_a_longer_token_fn_match_over_a_shorter_regex_match() {
+        let lexer = LexerBuilder::new()
+            .token(r"ab", Tok::A)
+            .token_fn(Tok::B, |s| s.starts_with("abc").then_some(3))
+            .longest_match()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            lexer.tokens("abc").collect::<Vec<_>>(),
+            vec![Token { kind: Tok::B, span: 0..3, text: "abc" }],
+        );
     }
 }